@@ -1,15 +1,31 @@
 use aes_gcm::aead::{Aead, OsRng};
 use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs;
+use std::sync::Mutex;
 use tauri::AppHandle;
 use tauri::Manager;
 use tauri_plugin_shell::ShellExt;
 use thiserror::Error;
 
 pub const NONCE_SIZE: usize = 12;
+pub const SALT_SIZE: usize = 16;
+
+/// Current version of the on-disk key-derivation parameters. Bump this when
+/// the Argon2 cost defaults change so old stores keep deriving with the
+/// parameters they were created with.
+pub const KDF_VERSION: u32 = 1;
+
+/// Argon2id cost parameters. Chosen for an interactive desktop unlock:
+/// 64 MiB of memory, three passes, single lane.
+pub const ARGON2_M_COST: u32 = 64 * 1024;
+pub const ARGON2_T_COST: u32 = 3;
+pub const ARGON2_P_COST: u32 = 1;
 
 #[derive(Debug, Error)]
 pub enum CryptoError {
@@ -31,6 +47,91 @@ pub enum CryptoError {
     KeyDerivationError,
     #[error("Shell command execution failed: {0}")]
     ShellCommandError(String),
+    #[error("Argon2 key derivation failed: {0}")]
+    Argon2Error(String),
+    #[error("Key store is locked; a master password is required")]
+    Locked,
+    #[error("Incorrect master password")]
+    WrongPassword,
+    #[error("Failed to parse key-derivation parameters: {0}")]
+    ParamsError(#[from] serde_json::Error),
+    #[error("Settings integrity check failed; the store may have been tampered with")]
+    TamperDetected,
+    #[error("Unsupported settings schema version: {0}")]
+    UnsupportedSchemaVersion(u32),
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Compute an HMAC-SHA256 tag over `data`, keyed by the derived master key.
+/// Used to authenticate the overall structure of the settings store (schema
+/// version, non-secret fields, and the set of provider IDs) on top of the
+/// per-key AES-GCM authentication.
+pub fn compute_hmac(key: &Key<Aes256Gcm>, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key.as_slice())
+        .map_err(|_| CryptoError::KeyDerivationError)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Fixed plaintext sealed under a generation's key to form a password verifier.
+/// Decrypting the stored ciphertext back to exactly this value proves the
+/// supplied password derived the right key, so a wrong password can be rejected
+/// up front instead of silently producing a key that fails everything later.
+const VERIFIER_PLAINTEXT: &str = "chatalia:key-verifier:v1";
+
+/// On-disk description of how one key generation is derived. Only the salt, the
+/// public Argon2 cost parameters, and a password verifier are persisted — never
+/// the password or the derived key itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GenerationParams {
+    /// Monotonically increasing key generation, bumped on every rotation. Used
+    /// to stamp each stored ciphertext so rotation can skip already-migrated
+    /// values. Defaults to 1 for stores written before rotation existed.
+    #[serde(default = "default_generation")]
+    generation: u32,
+    /// Base64-encoded 16-byte random salt.
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// Base64 AES-GCM ciphertext of [`VERIFIER_PLAINTEXT`] under this
+    /// generation's key. Absent on stores written before the verifier existed;
+    /// the first successful unlock backfills it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verifier: Option<String>,
+}
+
+fn default_generation() -> u32 {
+    1
+}
+
+impl GenerationParams {
+    fn generate(generation: u32) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        GenerationParams {
+            generation,
+            salt: BASE64.encode(salt),
+            m_cost: ARGON2_M_COST,
+            t_cost: ARGON2_T_COST,
+            p_cost: ARGON2_P_COST,
+            verifier: None,
+        }
+    }
+
+    fn salt_bytes(&self) -> Result<Vec<u8>, CryptoError> {
+        Ok(BASE64.decode(&self.salt)?)
+    }
+}
+
+/// On-disk `key.params`: the set of generation parameters, newest last. Keeping
+/// more than one entry lets the store derive both the old and the new key while
+/// a rotation is in flight, which is what makes a rotation crash-safe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyParamsFile {
+    version: u32,
+    generations: Vec<GenerationParams>,
 }
 
 async fn execute_command_async(
@@ -112,58 +213,309 @@ pub async fn get_machine_id(app_handle: &AppHandle) -> Result<String, CryptoErro
     get_machine_id_os(app_handle).await
 }
 
-async fn generate_device_key(app_handle: &AppHandle) -> Result<Key<Aes256Gcm>, CryptoError> {
+fn params_path(app_handle: &AppHandle) -> Result<std::path::PathBuf, CryptoError> {
+    app_handle
+        .path()
+        .app_data_dir()
+        .map(|dir| dir.join("key.params"))
+        .map_err(|e| {
+            eprintln!("Tauri path resolver error: {}", e);
+            CryptoError::AppDataDirError
+        })
+}
+
+/// Read the stored derivation parameters, tolerating both the current
+/// multi-generation layout and the original single-object one so stores written
+/// by older builds keep loading. Returns `None` when no parameters exist yet.
+fn load_params_file(app_handle: &AppHandle) -> Result<Option<KeyParamsFile>, CryptoError> {
+    let path = params_path(app_handle)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path)?;
+    // Current format: a set of generation entries.
+    if let Ok(file) = serde_json::from_str::<KeyParamsFile>(&contents) {
+        if !file.generations.is_empty() {
+            return Ok(Some(file));
+        }
+    }
+    // Legacy format: a single flat parameter object. Wrap it as generation one.
+    let legacy: GenerationParams = serde_json::from_str(&contents)?;
+    Ok(Some(KeyParamsFile {
+        version: KDF_VERSION,
+        generations: vec![legacy],
+    }))
+}
+
+/// Whether the store already has a master password configured (i.e. the
+/// derivation parameters have been written). The frontend uses this to decide
+/// between a "set password" and an "unlock" prompt.
+pub fn has_master_password(app_handle: &AppHandle) -> Result<bool, CryptoError> {
+    Ok(params_path(app_handle)?.exists())
+}
+
+/// Run Argon2id over `password` using the given parameters. The stored salt is
+/// mixed with the machine ID so the resulting ciphertext stays device-bound
+/// even though the password is the real root of trust.
+async fn derive_key_with_params(
+    app_handle: &AppHandle,
+    params: &GenerationParams,
+    password: &str,
+) -> Result<Key<Aes256Gcm>, CryptoError> {
+    // Device binding: fold the machine ID into the salt. A machine ID that
+    // cannot be read is tolerated rather than failing the whole derivation;
+    // an empty ID is folded in instead, which yields a different key than the
+    // one derived with the real ID.
+    let machine_id = get_machine_id(app_handle).await.unwrap_or_default();
+    let mut salt_hasher = Sha256::default();
+    salt_hasher.update(params.salt_bytes()?);
+    salt_hasher.update(machine_id.as_bytes());
+    let effective_salt = salt_hasher.finalize();
+
+    let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| CryptoError::Argon2Error(e.to_string()))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+
+    let mut key_bytes = [0u8; 32];
+    argon
+        .hash_password_into(password.as_bytes(), &effective_salt, &mut key_bytes)
+        .map_err(|e| CryptoError::Argon2Error(e.to_string()))?;
+
+    Ok(Key::<Aes256Gcm>::clone_from_slice(&key_bytes))
+}
+
+/// Confirm `key` reproduces the stored verifier, returning
+/// [`CryptoError::WrongPassword`] when it does not (a bad password, or a key
+/// that simply was not the one this verifier was sealed with).
+fn check_verifier(key: &Key<Aes256Gcm>, verifier: &str) -> Result<(), CryptoError> {
+    match decrypt_base64(key, verifier) {
+        Ok(plaintext) if plaintext == VERIFIER_PLAINTEXT => Ok(()),
+        _ => Err(CryptoError::WrongPassword),
+    }
+}
+
+/// Derive the AES-256-GCM key for the store's active `generation` from the
+/// user's master password with Argon2id, creating the parameters on first run.
+///
+/// The first time a generation's key is derived there is no verifier to compare
+/// against, so one is sealed and persisted — this is what pins down a first-run
+/// password. On every later unlock the verifier is checked, so a wrong password
+/// fails with [`CryptoError::WrongPassword`] instead of silently deriving a key
+/// that only later makes the whole store look corrupt.
+pub async fn derive_key_from_password(
+    app_handle: &AppHandle,
+    password: &str,
+    generation: u32,
+) -> Result<Key<Aes256Gcm>, CryptoError> {
+    let mut file = load_params_file(app_handle)?.unwrap_or_else(|| KeyParamsFile {
+        version: KDF_VERSION,
+        generations: vec![GenerationParams::generate(generation)],
+    });
+
+    // Use the entry for the active generation, falling back to the newest one
+    // if the requested generation is somehow missing.
+    let idx = file
+        .generations
+        .iter()
+        .position(|g| g.generation == generation)
+        .unwrap_or_else(|| {
+            file.generations
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, g)| g.generation)
+                .map(|(i, _)| i)
+                .unwrap_or(0)
+        });
+
+    let key = derive_key_with_params(app_handle, &file.generations[idx], password).await?;
+    match file.generations[idx].verifier.clone() {
+        Some(verifier) => check_verifier(&key, &verifier)?,
+        None => {
+            file.generations[idx].verifier = Some(encrypt_to_base64(&key, VERIFIER_PLAINTEXT)?);
+            write_params_file(app_handle, &file)?;
+        }
+    }
+    Ok(key)
+}
+
+/// Confirm `key` matches the persisted verifier for `generation`, returning
+/// [`CryptoError::WrongPassword`] if not. A store with no parameters or no
+/// verifier yet is accepted — there is nothing to check against.
+pub fn verify_key(
+    app_handle: &AppHandle,
+    key: &Key<Aes256Gcm>,
+    generation: u32,
+) -> Result<(), CryptoError> {
+    let file = match load_params_file(app_handle)? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    match file
+        .generations
+        .iter()
+        .find(|g| g.generation == generation)
+        .and_then(|g| g.verifier.as_deref())
+    {
+        Some(verifier) => check_verifier(key, verifier),
+        None => Ok(()),
+    }
+}
+
+/// Reconstruct the pre-Argon2 device key used by older builds:
+/// `SHA256(machine_id || key.salt)`. Retained only so the one-time importer can
+/// decrypt a legacy `settings.json` and re-encrypt it under the new master key;
+/// it is never used to write new ciphertext. Errors if the legacy `key.salt`
+/// is absent (nothing to migrate).
+pub async fn legacy_device_key(app_handle: &AppHandle) -> Result<Key<Aes256Gcm>, CryptoError> {
+    let app_data_dir = app_handle.path().app_data_dir().map_err(|e| {
+        eprintln!("Tauri path resolver error: {}", e);
+        CryptoError::AppDataDirError
+    })?;
+    let random_salt = fs::read(app_data_dir.join("key.salt"))?;
     let machine_id = get_machine_id(app_handle).await?;
-    let mut random_salt: [u8; 16] = [0; 16];
-    OsRng.fill_bytes(&mut random_salt);
 
     let mut hasher = Sha256::default();
     hasher.update(machine_id.as_bytes());
     hasher.update(&random_salt);
     let key_bytes = hasher.finalize();
 
-    let app_data_dir = app_handle
-        .path()
-        .app_data_dir() 
-        .map_err(|e| {
-            eprintln!("Tauri path resolver error: {}", e); 
-            CryptoError::AppDataDirError
-        })?;
+    Ok(Key::<Aes256Gcm>::clone_from_slice(key_bytes.as_slice()))
+}
 
-    fs::create_dir_all(&app_data_dir)?;
-    fs::write(app_data_dir.join("key.salt"), &random_salt)?;
+/// The newest key generation for which parameters exist, or 1 if none have been
+/// written yet.
+fn highest_generation(app_handle: &AppHandle) -> Result<u32, CryptoError> {
+    Ok(load_params_file(app_handle)?
+        .and_then(|f| f.generations.iter().map(|g| g.generation).max())
+        .unwrap_or(1))
+}
 
-    Ok(Key::<Aes256Gcm>::clone_from_slice(key_bytes.as_slice()))
+/// Persist the derivation parameters by writing to a sibling temp file and
+/// renaming it over the target, so an interrupted write can never leave a
+/// half-written `key.params` behind.
+fn write_params_file(app_handle: &AppHandle, file: &KeyParamsFile) -> Result<(), CryptoError> {
+    let path = params_path(app_handle)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("params.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(file)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
 }
 
-pub async fn get_key(app_handle: &AppHandle) -> Result<Key<Aes256Gcm>, CryptoError> {
-    let app_data_dir = app_handle
-        .path()
-        .app_data_dir() 
-        .map_err(|e| {
-            eprintln!("Tauri path resolver error: {}", e);
-            CryptoError::AppDataDirError 
-        })?;
-    let salt_path = app_data_dir.join("key.salt");
+/// A derived rotation whose new parameters are already written alongside the
+/// old ones, so both key generations are derivable. The caller re-encrypts the
+/// stored values with [`PendingRotation::key`] and then
+/// [`PendingRotation::commit`]s to drop the superseded generation. Because both
+/// generations stay on disk across the re-encryption, the store unlocks at every
+/// crash point rather than relying on a single fragile write ordering.
+pub struct PendingRotation {
+    new_entry: GenerationParams,
+    key: Key<Aes256Gcm>,
+    generation: u32,
+}
 
-    if salt_path.exists() {
-        let random_salt = fs::read(salt_path)?;
-        let machine_id = get_machine_id(app_handle).await?; // await machine ID
+impl PendingRotation {
+    pub fn key(&self) -> &Key<Aes256Gcm> {
+        &self.key
+    }
 
-        let mut hasher = Sha256::default();
-        hasher.update(machine_id.as_bytes());
-        hasher.update(&random_salt);
-        let key_bytes = hasher.finalize();
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
 
-        Ok(Key::<Aes256Gcm>::clone_from_slice(key_bytes.as_slice()))
-    } else {
-        generate_device_key(app_handle).await
+    /// Finalize the rotation by pruning the superseded generations, leaving only
+    /// the new one, and return the new key.
+    pub fn commit(self, app_handle: &AppHandle) -> Result<Key<Aes256Gcm>, CryptoError> {
+        write_params_file(
+            app_handle,
+            &KeyParamsFile {
+                version: KDF_VERSION,
+                generations: vec![self.new_entry],
+            },
+        )?;
+        Ok(self.key)
     }
 }
 
-pub async fn encrypt(app_handle: &AppHandle, text: &str) -> Result<Vec<u8>, CryptoError> {
-    let key = get_key(app_handle).await?;
-    let cipher = Aes256Gcm::new(&key);
+/// Mint a fresh master key for rotation: generate a new salt, bump the
+/// generation, derive the key from `new_password`, and write the new parameters
+/// *alongside* the existing ones so both generations can be derived. Only then
+/// is it safe to re-encrypt the store: if the process dies partway, the old key
+/// still derives (nothing committed) or the new key does (sweep committed), so
+/// there is no ordering that locks the store out. [`PendingRotation::commit`]
+/// prunes the old generation once the sweep has committed.
+pub async fn prepare_rotation(
+    app_handle: &AppHandle,
+    new_password: &str,
+) -> Result<PendingRotation, CryptoError> {
+    let mut file = load_params_file(app_handle)?.ok_or(CryptoError::Locked)?;
+    let generation = highest_generation(app_handle)?.saturating_add(1);
+
+    let mut entry = GenerationParams::generate(generation);
+    let key = derive_key_with_params(app_handle, &entry, new_password).await?;
+    entry.verifier = Some(encrypt_to_base64(&key, VERIFIER_PLAINTEXT)?);
+
+    file.generations.push(entry.clone());
+    write_params_file(app_handle, &file)?;
+
+    Ok(PendingRotation {
+        new_entry: entry,
+        key,
+        generation,
+    })
+}
+
+/// Process-lifetime holder for the derived key. The key never touches disk; the
+/// store starts locked and is populated by [`KeyStore::unlock`] once the user
+/// supplies their master password.
+#[derive(Default)]
+pub struct KeyStore {
+    inner: Mutex<Option<Key<Aes256Gcm>>>,
+}
+
+impl KeyStore {
+    pub fn set(&self, key: Key<Aes256Gcm>) {
+        *self.inner.lock().unwrap() = Some(key);
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = None;
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.inner.lock().unwrap().is_some()
+    }
+
+    /// Return the derived key, or [`CryptoError::Locked`] if no password has
+    /// been supplied yet this session.
+    pub fn key(&self) -> Result<Key<Aes256Gcm>, CryptoError> {
+        self.inner
+            .lock()
+            .unwrap()
+            .as_ref()
+            .copied()
+            .ok_or(CryptoError::Locked)
+    }
+
+    /// Derive the key for the active `generation` from `password`, verifying the
+    /// password against the stored verifier, and cache it for the rest of the
+    /// session.
+    pub async fn unlock(
+        &self,
+        app_handle: &AppHandle,
+        password: &str,
+        generation: u32,
+    ) -> Result<(), CryptoError> {
+        let key = derive_key_from_password(app_handle, password, generation).await?;
+        self.set(key);
+        Ok(())
+    }
+}
+
+pub fn encrypt(key: &Key<Aes256Gcm>, text: &str) -> Result<Vec<u8>, CryptoError> {
+    let cipher = Aes256Gcm::new(key);
 
     let mut nonce_bytes = [0u8; NONCE_SIZE];
     OsRng.fill_bytes(&mut nonce_bytes);
@@ -180,7 +532,7 @@ pub async fn encrypt(app_handle: &AppHandle, text: &str) -> Result<Vec<u8>, Cryp
     Ok(result)
 }
 
-pub async fn decrypt(app_handle: &AppHandle, encrypted_data: &[u8]) -> Result<String, CryptoError> {
+pub fn decrypt(key: &Key<Aes256Gcm>, encrypted_data: &[u8]) -> Result<String, CryptoError> {
     if encrypted_data.len() <= NONCE_SIZE {
         return Err(CryptoError::FormatError);
     }
@@ -188,8 +540,7 @@ pub async fn decrypt(app_handle: &AppHandle, encrypted_data: &[u8]) -> Result<St
     let nonce = Nonce::from_slice(&encrypted_data[..NONCE_SIZE]);
     let ciphertext = &encrypted_data[NONCE_SIZE..];
 
-    let key = get_key(app_handle).await?;
-    let cipher = Aes256Gcm::new(&key);
+    let cipher = Aes256Gcm::new(key);
 
     let decrypted_bytes = cipher
         .decrypt(nonce, ciphertext)
@@ -200,15 +551,12 @@ pub async fn decrypt(app_handle: &AppHandle, encrypted_data: &[u8]) -> Result<St
     Ok(decrypted_string)
 }
 
-pub async fn encrypt_to_base64(app_handle: &AppHandle, text: &str) -> Result<String, CryptoError> {
-    let encrypted_bytes = encrypt(app_handle, text).await?;
+pub fn encrypt_to_base64(key: &Key<Aes256Gcm>, text: &str) -> Result<String, CryptoError> {
+    let encrypted_bytes = encrypt(key, text)?;
     Ok(BASE64.encode(encrypted_bytes))
 }
 
-pub async fn decrypt_base64(
-    app_handle: &AppHandle,
-    base64_text: &str,
-) -> Result<String, CryptoError> {
+pub fn decrypt_base64(key: &Key<Aes256Gcm>, base64_text: &str) -> Result<String, CryptoError> {
     let encrypted_data = BASE64.decode(base64_text)?;
-    decrypt(app_handle, &encrypted_data).await
+    decrypt(key, &encrypted_data)
 }
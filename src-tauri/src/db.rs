@@ -0,0 +1,542 @@
+use crate::crypto::{self, CryptoError};
+use crate::secret::SecretString;
+use crate::settings::{default_chat_settings, ApiProviderConfig, ChatSettings};
+use aes_gcm::{Aes256Gcm, Key};
+use serde::Serialize;
+use sqlx::sqlite::{SqlitePoolOptions, SqliteRow};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashSet;
+use std::fs;
+use tauri::{AppHandle, Manager};
+use thiserror::Error;
+
+const META_CHAT_SETTINGS: &str = "default_chat_settings";
+const META_SEND_WITH_ENTER: &str = "send_with_enter";
+const META_SCHEMA_VERSION: &str = "schema_version";
+const META_MANIFEST: &str = "manifest";
+const META_KEY_GENERATION: &str = "key_generation";
+
+/// Version of the settings schema this build writes. Bump it whenever the
+/// on-disk layout changes so older stores are detected rather than mis-parsed.
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("Database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("Migration error: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to get app data directory")]
+    AppDataDirError,
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Managed handle to the encrypted SQLite store.
+pub struct Db(pub SqlitePool);
+
+/// Open (creating if necessary) the SQLite store under the app data directory
+/// and run any pending migrations.
+pub async fn init(app_handle: &AppHandle) -> Result<SqlitePool, DbError> {
+    let dir = app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|_| DbError::AppDataDirError)?;
+    fs::create_dir_all(&dir)?;
+
+    let db_path = dir.join("chatalia.db");
+    let url = format!("sqlite://{}?mode=rwc", db_path.to_string_lossy());
+
+    let pool = SqlitePoolOptions::new().max_connections(4).connect(&url).await?;
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    Ok(pool)
+}
+
+fn row_to_provider(row: &SqliteRow) -> ApiProviderConfig {
+    // The stored ciphertext is kept in the SecretString as-is; SecretString's
+    // Serialize redacts it, so load_app_settings never hands plaintext to the
+    // frontend. Plaintext is served on demand by `provider_api_key`.
+    let ciphertext: String = row.get("api_key");
+    ApiProviderConfig {
+        id: row.get("id"),
+        provider_id: row.get("provider_id"),
+        name: row.get("name"),
+        api_key: SecretString::new(ciphertext),
+        base_url: row.get("base_url"),
+    }
+}
+
+/// Load every provider. Stored keys stay encrypted in the returned values;
+/// plaintext is fetched separately through [`provider_api_key`].
+pub async fn load_providers(pool: &SqlitePool) -> Result<Vec<ApiProviderConfig>, DbError> {
+    let rows = sqlx::query("SELECT id, provider_id, name, api_key, base_url FROM providers")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(row_to_provider).collect())
+}
+
+/// The ciphertext currently stored for a provider, if it exists.
+async fn existing_ciphertext(pool: &SqlitePool, id: &str) -> Result<Option<String>, DbError> {
+    Ok(sqlx::query("SELECT api_key FROM providers WHERE id = ?1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .map(|r| r.get::<String, _>("api_key")))
+}
+
+/// Decrypt the stored key for a single provider and return the plaintext. This
+/// is the only path that reveals a key, so the frontend calls it explicitly
+/// when it needs to use one rather than receiving keys in bulk from the load.
+pub async fn provider_api_key(
+    pool: &SqlitePool,
+    key: &Key<Aes256Gcm>,
+    id: &str,
+) -> Result<Option<String>, DbError> {
+    match existing_ciphertext(pool, id).await? {
+        Some(ciphertext) if !ciphertext.is_empty() => {
+            Ok(Some(crypto::decrypt_base64(key, &ciphertext)?))
+        }
+        Some(_) => Ok(Some(String::new())),
+        None => Ok(None),
+    }
+}
+
+/// Resolve the ciphertext to store for `provider`: preserve the existing value
+/// when the frontend echoed back the redaction marker, clear it when empty, or
+/// encrypt a freshly entered plaintext otherwise.
+async fn resolve_ciphertext(
+    pool: &SqlitePool,
+    key: &Key<Aes256Gcm>,
+    provider: &ApiProviderConfig,
+) -> Result<String, DbError> {
+    if provider.api_key.is_redacted() {
+        Ok(existing_ciphertext(pool, &provider.id)
+            .await?
+            .unwrap_or_default())
+    } else if provider.api_key.is_empty() {
+        Ok(String::new())
+    } else {
+        Ok(crypto::encrypt_to_base64(key, provider.api_key.expose())?)
+    }
+}
+
+/// Insert or update a single provider inside its own transaction, re-encrypting
+/// the key at the column level. This replaces the old whole-file rewrite so a
+/// single provider change no longer risks the rest of the config.
+pub async fn upsert_provider(
+    pool: &SqlitePool,
+    key: &Key<Aes256Gcm>,
+    provider: &ApiProviderConfig,
+) -> Result<(), DbError> {
+    let ciphertext = resolve_ciphertext(pool, key, provider).await?;
+    let generation = current_generation(pool).await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO providers (id, provider_id, name, api_key, base_url, key_version) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+         ON CONFLICT(id) DO UPDATE SET \
+             provider_id = excluded.provider_id, \
+             name = excluded.name, \
+             api_key = excluded.api_key, \
+             base_url = excluded.base_url, \
+             key_version = excluded.key_version",
+    )
+    .bind(&provider.id)
+    .bind(&provider.provider_id)
+    .bind(&provider.name)
+    .bind(&ciphertext)
+    .bind(&provider.base_url)
+    .bind(generation as i64)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Replace the provider set with `providers` in a single transaction: delete
+/// any row whose id is absent from the incoming list, then upsert each incoming
+/// provider. This restores the baseline whole-file semantics where removing a
+/// provider from the settings object deletes it, without giving up column-level
+/// encryption or transactional safety.
+pub async fn sync_providers(
+    pool: &SqlitePool,
+    key: &Key<Aes256Gcm>,
+    providers: &[ApiProviderConfig],
+) -> Result<(), DbError> {
+    let generation = current_generation(pool).await?;
+
+    // Resolve ciphertexts (which may read existing rows for redacted entries)
+    // before opening the write transaction.
+    let mut resolved: Vec<(&ApiProviderConfig, String)> = Vec::with_capacity(providers.len());
+    for provider in providers {
+        resolved.push((provider, resolve_ciphertext(pool, key, provider).await?));
+    }
+
+    let incoming: HashSet<&str> = providers.iter().map(|p| p.id.as_str()).collect();
+    let existing = provider_ids(pool).await?;
+
+    let mut tx = pool.begin().await?;
+    for id in &existing {
+        if !incoming.contains(id.as_str()) {
+            sqlx::query("DELETE FROM providers WHERE id = ?1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    for (provider, ciphertext) in &resolved {
+        sqlx::query(
+            "INSERT INTO providers (id, provider_id, name, api_key, base_url, key_version) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+             ON CONFLICT(id) DO UPDATE SET \
+                 provider_id = excluded.provider_id, \
+                 name = excluded.name, \
+                 api_key = excluded.api_key, \
+                 base_url = excluded.base_url, \
+                 key_version = excluded.key_version",
+        )
+        .bind(&provider.id)
+        .bind(&provider.provider_id)
+        .bind(&provider.name)
+        .bind(ciphertext)
+        .bind(&provider.base_url)
+        .bind(generation as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The key generation new values are currently encrypted under, or 1 if the
+/// store predates rotation.
+pub async fn current_generation(pool: &SqlitePool) -> Result<u32, DbError> {
+    Ok(get_meta(pool, META_KEY_GENERATION)
+        .await?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1))
+}
+
+/// Remove a provider by id.
+pub async fn delete_provider(pool: &SqlitePool, id: &str) -> Result<(), DbError> {
+    sqlx::query("DELETE FROM providers WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn get_meta(pool: &SqlitePool, key: &str) -> Result<Option<String>, DbError> {
+    let row = sqlx::query("SELECT value FROM app_meta WHERE key = ?1")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|r| r.get::<String, _>("value")))
+}
+
+async fn set_meta(pool: &SqlitePool, key: &str, value: &str) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO app_meta (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+    )
+    .bind(key)
+    .bind(value)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Transaction-scoped variants of the meta accessors, used where a write has to
+/// land atomically with other changes in the same transaction rather than on
+/// its own connection.
+async fn set_meta_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &str,
+    value: &str,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO app_meta (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+    )
+    .bind(key)
+    .bind(value)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+async fn get_meta_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &str,
+) -> Result<Option<String>, DbError> {
+    let row = sqlx::query("SELECT value FROM app_meta WHERE key = ?1")
+        .bind(key)
+        .fetch_optional(&mut **tx)
+        .await?;
+    Ok(row.map(|r| r.get::<String, _>("value")))
+}
+
+/// Load the non-provider settings: the default chat settings and the
+/// send-with-enter flag, each falling back to its default when absent.
+pub async fn load_app_meta(pool: &SqlitePool) -> Result<(ChatSettings, bool), DbError> {
+    let chat = match get_meta(pool, META_CHAT_SETTINGS).await? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => default_chat_settings(),
+    };
+    let send_with_enter = get_meta(pool, META_SEND_WITH_ENTER)
+        .await?
+        .map(|v| v == "true")
+        .unwrap_or(true);
+    Ok((chat, send_with_enter))
+}
+
+pub async fn save_app_meta(
+    pool: &SqlitePool,
+    chat: &ChatSettings,
+    send_with_enter: bool,
+) -> Result<(), DbError> {
+    set_meta(pool, META_CHAT_SETTINGS, &serde_json::to_string(chat)?).await?;
+    set_meta(
+        pool,
+        META_SEND_WITH_ENTER,
+        if send_with_enter { "true" } else { "false" },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Canonical, non-secret view of the store that the integrity manifest is
+/// computed over. Secret material (the encrypted keys themselves) is already
+/// authenticated by AES-GCM, so only the structure is covered here.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest<'a> {
+    schema_version: u32,
+    default_chat_settings: &'a ChatSettings,
+    send_with_enter: bool,
+    provider_ids: Vec<String>,
+}
+
+async fn provider_ids(pool: &SqlitePool) -> Result<Vec<String>, DbError> {
+    let rows = sqlx::query("SELECT id FROM providers ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|r| r.get::<String, _>("id")).collect())
+}
+
+fn compute_manifest(
+    key: &Key<Aes256Gcm>,
+    chat: &ChatSettings,
+    send_with_enter: bool,
+    mut ids: Vec<String>,
+) -> Result<String, DbError> {
+    ids.sort();
+    let manifest = Manifest {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        default_chat_settings: chat,
+        send_with_enter,
+        provider_ids: ids,
+    };
+    let canonical = serde_json::to_vec(&manifest)?;
+    let tag = crypto::compute_hmac(key, &canonical)?;
+    Ok(tag.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Recompute and persist the schema version and integrity manifest from the
+/// current store contents. Call this after any mutation so the stored tag
+/// stays in sync with what is actually on disk.
+pub async fn refresh_manifest(pool: &SqlitePool, key: &Key<Aes256Gcm>) -> Result<(), DbError> {
+    let (chat, send_with_enter) = load_app_meta(pool).await?;
+    let ids = provider_ids(pool).await?;
+    let tag = compute_manifest(key, &chat, send_with_enter, ids)?;
+    set_meta(pool, META_SCHEMA_VERSION, &CURRENT_SCHEMA_VERSION.to_string()).await?;
+    set_meta(pool, META_MANIFEST, &tag).await?;
+    Ok(())
+}
+
+/// Re-encrypt every stored credential from `old_key` to `new_key`, stamping
+/// each with `new_generation`. The re-encryption, the generation marker, and
+/// the integrity manifest are all written in a single SQLite transaction, so
+/// the providers, the active generation, and the manifest can never be
+/// committed out of step with one another — an interrupted rotation either
+/// fully applies or leaves the store untouched. Values already at
+/// `new_generation` are skipped so a re-run is idempotent, and empty keys are
+/// bumped without any crypto work.
+pub async fn rotate_keys(
+    pool: &SqlitePool,
+    old_key: &Key<Aes256Gcm>,
+    new_key: &Key<Aes256Gcm>,
+    new_generation: u32,
+) -> Result<(), DbError> {
+    let rows = sqlx::query("SELECT id, api_key, key_version FROM providers")
+        .fetch_all(pool)
+        .await?;
+
+    let mut tx = pool.begin().await?;
+    for row in &rows {
+        let version: i64 = row.get("key_version");
+        if version as u32 == new_generation {
+            continue; // already migrated on a previous (interrupted) run
+        }
+
+        let ciphertext: String = row.get("api_key");
+        let reencrypted = if ciphertext.is_empty() {
+            String::new()
+        } else {
+            let plaintext = crypto::decrypt_base64(old_key, &ciphertext)?;
+            crypto::encrypt_to_base64(new_key, &plaintext)?
+        };
+
+        let id: String = row.get("id");
+        sqlx::query("UPDATE providers SET api_key = ?1, key_version = ?2 WHERE id = ?3")
+            .bind(&reencrypted)
+            .bind(new_generation as i64)
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // Bump the generation marker and recompute the manifest (over the rows as
+    // they now stand in this transaction) before committing, so all three land
+    // together.
+    set_meta_tx(&mut tx, META_KEY_GENERATION, &new_generation.to_string()).await?;
+
+    let chat = match get_meta_tx(&mut tx, META_CHAT_SETTINGS).await? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => default_chat_settings(),
+    };
+    let send_with_enter = get_meta_tx(&mut tx, META_SEND_WITH_ENTER)
+        .await?
+        .map(|v| v == "true")
+        .unwrap_or(true);
+    let ids = sqlx::query("SELECT id FROM providers ORDER BY id")
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|r| r.get::<String, _>("id"))
+        .collect();
+    let tag = compute_manifest(new_key, &chat, send_with_enter, ids)?;
+    set_meta_tx(&mut tx, META_SCHEMA_VERSION, &CURRENT_SCHEMA_VERSION.to_string()).await?;
+    set_meta_tx(&mut tx, META_MANIFEST, &tag).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Verify the stored manifest before the contents are trusted. A newer schema
+/// version is rejected as unsupported, a manifest mismatch is reported as
+/// tampering, and a store without a manifest yet (fresh or just imported) is
+/// trusted on first use and stamped so later loads are protected.
+///
+/// A wrong master password derives a key that fails the manifest HMAC exactly
+/// like real tampering would, so the password verifier is checked first: a bad
+/// password surfaces as [`CryptoError::WrongPassword`] and only a key that is
+/// known-correct can reach — and trip — the tamper path.
+pub async fn verify_manifest(
+    pool: &SqlitePool,
+    app_handle: &AppHandle,
+    key: &Key<Aes256Gcm>,
+) -> Result<(), DbError> {
+    let generation = current_generation(pool).await?;
+    crypto::verify_key(app_handle, key, generation)?;
+
+    match (
+        get_meta(pool, META_SCHEMA_VERSION).await?,
+        get_meta(pool, META_MANIFEST).await?,
+    ) {
+        (Some(version), Some(stored)) => {
+            let version: u32 = version.parse().map_err(|_| CryptoError::FormatError)?;
+            if version > CURRENT_SCHEMA_VERSION {
+                return Err(CryptoError::UnsupportedSchemaVersion(version).into());
+            }
+            let (chat, send_with_enter) = load_app_meta(pool).await?;
+            let ids = provider_ids(pool).await?;
+            let expected = compute_manifest(key, &chat, send_with_enter, ids)?;
+            if expected != stored {
+                return Err(CryptoError::TamperDetected.into());
+            }
+            Ok(())
+        }
+        _ => refresh_manifest(pool, key).await,
+    }
+}
+
+/// One-time importer: if the store has no providers yet but a legacy
+/// `settings.json` exists, decrypt its contents and insert them as rows so
+/// existing users upgrade to the database seamlessly. The old file is left in
+/// place as a backup; the import is skipped once the store has providers or
+/// saved chat settings, which makes re-running a no-op.
+///
+/// The legacy file was encrypted with the removed machine-ID key
+/// (`SHA256(machine_id || key.salt)`), not the current Argon2id master key, so
+/// its keys are decrypted with [`crypto::legacy_device_key`] and then
+/// re-encrypted under the new `key`. If the legacy key cannot be reconstructed
+/// (no `key.salt`), the import is skipped rather than aborting the load.
+pub async fn import_legacy_json(
+    pool: &SqlitePool,
+    app_handle: &AppHandle,
+    key: &Key<Aes256Gcm>,
+) -> Result<(), DbError> {
+    let count: i64 = sqlx::query("SELECT COUNT(*) AS n FROM providers")
+        .fetch_one(pool)
+        .await?
+        .get("n");
+    // A completed import always saves the chat settings, so their presence
+    // marks the import as done even after every provider has been deleted.
+    if count > 0 || get_meta(pool, META_CHAT_SETTINGS).await?.is_some() {
+        return Ok(());
+    }
+
+    let dir = app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|_| DbError::AppDataDirError)?;
+    let path = dir.join("settings.json");
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    if contents.trim().is_empty() {
+        return Ok(());
+    }
+
+    let legacy_key = match crypto::legacy_device_key(app_handle).await {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("Skipping legacy settings.json import; legacy key unavailable: {}", e);
+            return Ok(());
+        }
+    };
+
+    println!("Importing legacy settings.json into the database...");
+    let legacy: crate::settings::AppSettings = serde_json::from_str(&contents)?;
+
+    for mut provider in legacy.api_providers {
+        // settings.json holds ciphertext under the old machine-ID key: decrypt
+        // with the legacy key, then let upsert_provider re-encrypt under the
+        // new master key. A key that fails to decrypt is imported empty so one
+        // bad entry cannot abort the whole upgrade.
+        if !provider.api_key.is_empty() {
+            match crypto::decrypt_base64(&legacy_key, provider.api_key.expose()) {
+                Ok(plaintext) => provider.api_key.replace(plaintext),
+                Err(e) => {
+                    eprintln!(
+                        "WARN: Failed to decrypt legacy key for '{}': {}",
+                        provider.name, e
+                    );
+                    provider.api_key.replace(String::new());
+                }
+            }
+        }
+        upsert_provider(pool, key, &provider).await?;
+    }
+    save_app_meta(pool, &legacy.default_chat_settings, legacy.send_with_enter).await?;
+    println!("Legacy import complete.");
+    Ok(())
+}
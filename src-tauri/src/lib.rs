@@ -1,5 +1,8 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 mod crypto;
+mod db;
+mod recovery;
+mod secret;
 mod settings;
 use tauri::Manager;
 
@@ -10,9 +13,20 @@ pub fn run() {
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_os::init())
+        .manage(crypto::KeyStore::default())
         .invoke_handler(tauri::generate_handler![
             settings::load_app_settings,
-            settings::save_app_settings
+            settings::save_app_settings,
+            settings::is_locked,
+            settings::has_master_password,
+            settings::unlock_settings,
+            settings::lock_settings,
+            recovery::export_recovery_phrase,
+            recovery::restore_from_recovery_phrase,
+            settings::save_provider,
+            settings::delete_provider,
+            settings::get_provider_api_key,
+            settings::rotate_encryption_key
             // Add other async commands like get_ai_response, test_api_connection later
         ])
         .setup(|app| {
@@ -32,6 +46,19 @@ pub fn run() {
                     } else {
                         println!("App data directory already exists.");
                     }
+
+                    // Open the SQLite store and run migrations up front so the
+                    // pool is ready in managed state before the first command.
+                    match tauri::async_runtime::block_on(db::init(&app_handle)) {
+                        Ok(pool) => {
+                            app.manage(db::Db(pool));
+                            println!("Settings database initialized.");
+                        }
+                        Err(e) => {
+                            eprintln!("FATAL: Failed to initialize settings database: {}", e);
+                        }
+                    }
+
                     println!("Setup complete. Key check deferred to first use.");
                 }
                 Err(e) => { 
@@ -0,0 +1,226 @@
+use crate::crypto::KeyStore;
+use aes_gcm::{Aes256Gcm, Key};
+use bip39::Language;
+use sha2::{Digest, Sha256};
+use tauri::State;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RecoveryError {
+    #[error("Entropy must be 16 or 32 bytes")]
+    InvalidEntropyLength,
+    #[error("A recovery phrase must have 12 or 24 words")]
+    InvalidWordCount,
+    #[error("Recovery phrase contains a word outside the English list: {0}")]
+    UnknownWord(String),
+    #[error("Recovery phrase checksum does not match")]
+    ChecksumMismatch,
+    #[error("Key store is locked; unlock before exporting a recovery phrase")]
+    Locked,
+}
+
+/// Encode raw entropy as a BIP39 English mnemonic: append a checksum equal to
+/// the first `entropy_bits / 32` bits of its SHA-256 digest, slice the
+/// combined bitstream into 11-bit groups, and map each group to the standard
+/// 2048-word list.
+fn entropy_to_phrase(entropy: &[u8]) -> Result<String, RecoveryError> {
+    if entropy.len() != 16 && entropy.len() != 32 {
+        return Err(RecoveryError::InvalidEntropyLength);
+    }
+
+    let entropy_bits = entropy.len() * 8;
+    let checksum_bits = entropy_bits / 32;
+
+    let hash = Sha256::digest(entropy);
+
+    let mut bits = Vec::with_capacity(entropy_bits + checksum_bits);
+    for &byte in entropy {
+        for i in (0..8).rev() {
+            bits.push((byte >> i) & 1 == 1);
+        }
+    }
+    for i in 0..checksum_bits {
+        bits.push((hash[i / 8] >> (7 - (i % 8))) & 1 == 1);
+    }
+
+    let words = Language::English.word_list();
+    let phrase = bits
+        .chunks(11)
+        .map(|chunk| {
+            let idx = chunk.iter().fold(0usize, |acc, &bit| (acc << 1) | bit as usize);
+            words[idx]
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Ok(phrase)
+}
+
+/// Reverse [`entropy_to_phrase`]: look each word up for its 11-bit index,
+/// concatenate the bitstream, split off the entropy and checksum, and verify
+/// the checksum before returning the entropy.
+fn phrase_to_entropy(phrase: &str) -> Result<Vec<u8>, RecoveryError> {
+    let words = Language::English.word_list();
+    let tokens: Vec<&str> = phrase.split_whitespace().collect();
+    if tokens.len() != 12 && tokens.len() != 24 {
+        return Err(RecoveryError::InvalidWordCount);
+    }
+
+    let mut bits = Vec::with_capacity(tokens.len() * 11);
+    for token in &tokens {
+        let idx = words
+            .iter()
+            .position(|w| *w == *token)
+            .ok_or_else(|| RecoveryError::UnknownWord((*token).to_string()))?;
+        for i in (0..11).rev() {
+            bits.push((idx >> i) & 1 == 1);
+        }
+    }
+
+    // total = entropy_bits + entropy_bits / 32, so entropy_bits = total * 32 / 33.
+    let checksum_bits = bits.len() / 33;
+    let entropy_bits = bits.len() - checksum_bits;
+
+    let mut entropy = vec![0u8; entropy_bits / 8];
+    for (i, bit) in bits[..entropy_bits].iter().enumerate() {
+        if *bit {
+            entropy[i / 8] |= 1 << (7 - (i % 8));
+        }
+    }
+
+    let hash = Sha256::digest(&entropy);
+    for i in 0..checksum_bits {
+        let expected = (hash[i / 8] >> (7 - (i % 8))) & 1 == 1;
+        if bits[entropy_bits + i] != expected {
+            return Err(RecoveryError::ChecksumMismatch);
+        }
+    }
+
+    Ok(entropy)
+}
+
+/// Reconstruct the AES-256-GCM key from recovered entropy. A 32-byte phrase
+/// carries the key material directly; a 16-byte phrase is stretched to 32
+/// bytes with SHA-256 so both phrase lengths yield a full-width key.
+fn key_from_entropy(entropy: &[u8]) -> Key<Aes256Gcm> {
+    let mut key_bytes = [0u8; 32];
+    if entropy.len() == 32 {
+        key_bytes.copy_from_slice(entropy);
+    } else {
+        key_bytes.copy_from_slice(&Sha256::digest(entropy));
+    }
+    Key::<Aes256Gcm>::clone_from_slice(&key_bytes)
+}
+
+/// Export the currently unlocked master key as a 24-word BIP39 phrase. Writing
+/// the phrase down lets the user rebuild the exact same key — and therefore
+/// decrypt the same settings database — after an OS reinstall or on a new
+/// machine. The store must be unlocked first.
+#[tauri::command]
+pub fn export_recovery_phrase(state: State<'_, KeyStore>) -> Result<String, String> {
+    let key = state.key().map_err(|_| RecoveryError::Locked.to_string())?;
+    entropy_to_phrase(key.as_slice()).map_err(|e| e.to_string())
+}
+
+/// Restore the master key from a recovery phrase and unlock the store with it,
+/// so an existing encrypted settings database decrypts without the original
+/// machine or master password.
+#[tauri::command]
+pub fn restore_from_recovery_phrase(
+    state: State<'_, KeyStore>,
+    phrase: String,
+) -> Result<(), String> {
+    let entropy = phrase_to_entropy(&phrase).map_err(|e| e.to_string())?;
+    state.set(key_from_entropy(&entropy));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Official BIP39 English test vectors (Trezor), keyed by a repeated-byte
+    // entropy so no hex decoder is needed to express them.
+    const ALL_ZERO_12: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    const ALL_ZERO_24: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+         abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+         abandon abandon abandon abandon abandon art";
+    const ALL_7F_12: &str =
+        "legal winner thank year wave sausage worth useful legal winner thank yellow";
+    const ALL_80_12: &str =
+        "letter advice cage absurd amount doctor acoustic avoid letter advice cage above";
+    const ALL_FF_12: &str = "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong";
+
+    #[test]
+    fn encodes_standard_vectors() {
+        assert_eq!(entropy_to_phrase(&[0x00; 16]).unwrap(), ALL_ZERO_12);
+        assert_eq!(entropy_to_phrase(&[0x00; 32]).unwrap(), ALL_ZERO_24);
+        assert_eq!(entropy_to_phrase(&[0x7f; 16]).unwrap(), ALL_7F_12);
+        assert_eq!(entropy_to_phrase(&[0x80; 16]).unwrap(), ALL_80_12);
+        assert_eq!(entropy_to_phrase(&[0xff; 16]).unwrap(), ALL_FF_12);
+    }
+
+    #[test]
+    fn decodes_standard_vectors() {
+        assert_eq!(phrase_to_entropy(ALL_ZERO_12).unwrap(), vec![0x00; 16]);
+        assert_eq!(phrase_to_entropy(ALL_ZERO_24).unwrap(), vec![0x00; 32]);
+        assert_eq!(phrase_to_entropy(ALL_7F_12).unwrap(), vec![0x7f; 16]);
+        assert_eq!(phrase_to_entropy(ALL_80_12).unwrap(), vec![0x80; 16]);
+        assert_eq!(phrase_to_entropy(ALL_FF_12).unwrap(), vec![0xff; 16]);
+    }
+
+    #[test]
+    fn round_trips_both_lengths() {
+        let entropy_16: Vec<u8> = (0..16).map(|i| (i * 17 + 3) as u8).collect();
+        let entropy_32: Vec<u8> = (0..32).map(|i| (i * 7 + 1) as u8).collect();
+        for entropy in [entropy_16, entropy_32] {
+            let phrase = entropy_to_phrase(&entropy).unwrap();
+            assert_eq!(phrase_to_entropy(&phrase).unwrap(), entropy);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_entropy_length() {
+        assert!(matches!(
+            entropy_to_phrase(&[0u8; 20]),
+            Err(RecoveryError::InvalidEntropyLength)
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_word_count() {
+        assert!(matches!(
+            phrase_to_entropy("abandon abandon about"),
+            Err(RecoveryError::InvalidWordCount)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_word() {
+        let phrase =
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon notaword";
+        assert!(matches!(
+            phrase_to_entropy(phrase),
+            Err(RecoveryError::UnknownWord(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        // The all-zero entropy ends in "about"; a trailing "abandon" instead
+        // leaves the word count valid but the checksum wrong.
+        let phrase =
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon";
+        assert!(matches!(
+            phrase_to_entropy(phrase),
+            Err(RecoveryError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn key_from_32_byte_entropy_is_verbatim() {
+        let entropy: Vec<u8> = (0..32).map(|i| i as u8).collect();
+        assert_eq!(key_from_entropy(&entropy).as_slice(), entropy.as_slice());
+    }
+}
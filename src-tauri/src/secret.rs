@@ -0,0 +1,80 @@
+use serde::de::Deserializer;
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Marker emitted in place of a configured secret when serializing. The
+/// frontend sends it back unchanged to mean "keep the existing value" and
+/// calls the explicit accessor command when it actually needs the plaintext.
+pub const REDACTED_MARKER: &str = "__REDACTED__";
+
+/// A string holding credential material that is zeroed on drop and never
+/// revealed through `Debug` or `Serialize`. The plaintext is reachable only
+/// through the explicit [`SecretString::expose`] accessor, so accidental
+/// logging, debug-dumping, or serialization cannot leak stored API keys.
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(value: String) -> Self {
+        SecretString(value)
+    }
+
+    /// Borrow the underlying secret. This is the only way to read the value;
+    /// call sites are deliberately explicit so leaks are easy to audit.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether this value is the redaction marker echoed back by the frontend,
+    /// meaning the stored secret should be left untouched.
+    pub fn is_redacted(&self) -> bool {
+        self.0 == REDACTED_MARKER
+    }
+
+    /// Overwrite the contents, zeroing the previous buffer first so the old
+    /// plaintext does not linger on the heap.
+    pub fn replace(&mut self, value: String) {
+        self.0.zeroize();
+        self.0 = value;
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Never serialize the secret itself. An empty value stays empty so the
+        // frontend can tell "no key set" from "key set"; any configured value
+        // is replaced by the redaction marker. Plaintext is obtained only
+        // through the explicit accessor command, so neither the Tauri IPC
+        // boundary nor a serde-based logger can capture a real key.
+        if self.0.is_empty() {
+            serializer.serialize_str("")
+        } else {
+            serializer.serialize_str(REDACTED_MARKER)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Ok(SecretString(raw))
+    }
+}
@@ -1,42 +1,43 @@
 use crate::crypto;
+use crate::crypto::KeyStore;
+use crate::db::{self, Db};
+use crate::secret::SecretString;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, State};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatSettings {
-    model: String,
-    temperature: f32,
+    pub(crate) model: String,
+    pub(crate) temperature: f32,
     #[serde(skip_serializing_if = "Option::is_none")]
-    system_prompt: Option<String>,
+    pub(crate) system_prompt: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u32>,
+    pub(crate) max_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<f32>,
+    pub(crate) top_p: Option<f32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ApiProviderConfig {
-    id: String,
-    provider_id: String,
-    name: String,
-    api_key: String,
+    pub(crate) id: String,
+    pub(crate) provider_id: String,
+    pub(crate) name: String,
+    pub(crate) api_key: SecretString,
     #[serde(skip_serializing_if = "Option::is_none")]
-    base_url: Option<String>,
+    pub(crate) base_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct AppSettings {
-    default_chat_settings: ChatSettings,
-    api_providers: Vec<ApiProviderConfig>,
-    send_with_enter: bool,
+    pub(crate) default_chat_settings: ChatSettings,
+    pub(crate) api_providers: Vec<ApiProviderConfig>,
+    pub(crate) send_with_enter: bool,
 }
 
-fn default_chat_settings() -> ChatSettings {
+pub(crate) fn default_chat_settings() -> ChatSettings {
     ChatSettings {
         model: "gpt-4o-mini".to_string(),
         temperature: 0.7,
@@ -46,96 +47,180 @@ fn default_chat_settings() -> ChatSettings {
     }
 }
 
-fn default_app_settings() -> AppSettings {
-    AppSettings {
-        default_chat_settings: default_chat_settings(),
-        api_providers: Vec::new(),
-        send_with_enter: true,
-    }
+/// Whether a master password must still be supplied before settings can be
+/// read or written this session. `true` means either no password is cached yet
+/// or (on first run) none has ever been configured.
+#[tauri::command]
+pub fn is_locked(state: State<'_, KeyStore>) -> bool {
+    !state.is_unlocked()
 }
 
-fn get_settings_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
-    app_handle
-        .path()
-        .app_data_dir()
-        .map(|dir| dir.join("settings.json"))
-        .map_err(|_| "Could not resolve app data directory".to_string())
+/// Whether the store already has a master password configured. The frontend
+/// uses this to choose between a first-run "set password" flow and an
+/// "unlock" prompt.
+#[tauri::command]
+pub fn has_master_password(app_handle: AppHandle) -> Result<bool, String> {
+    crypto::has_master_password(&app_handle).map_err(|e| e.to_string())
 }
 
+/// Derive and cache the master key from `password`, unlocking the store for the
+/// rest of the session. On first run this also persists a fresh salt and the
+/// Argon2 parameters.
 #[tauri::command]
-pub async fn load_app_settings(app_handle: AppHandle) -> Result<AppSettings, String> {
-    let path = get_settings_path(&app_handle)?;
-    println!("Attempting to load settings from: {:?}", path);
-
-    if !path.exists() {
-        println!("Settings file not found at specified path, returning defaults.");
-        return Ok(default_app_settings());
-    }
-
-    let contents =
-        fs::read_to_string(&path).map_err(|e| format!("Failed to read settings file: {}", e))?;
+pub async fn unlock_settings(
+    app_handle: AppHandle,
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    password: String,
+) -> Result<(), String> {
+    // Derive against the store's active key generation so a store that has been
+    // rotated unlocks with its current parameters.
+    let generation = db::current_generation(&db.0).await.map_err(|e| e.to_string())?;
+    state
+        .unlock(&app_handle, &password, generation)
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    if contents.trim().is_empty() {
-        return Ok(default_app_settings());
-    }
+/// Drop the cached master key, returning the store to its locked state.
+#[tauri::command]
+pub fn lock_settings(state: State<'_, KeyStore>) {
+    state.clear();
+}
 
-    let mut settings: AppSettings =
-        serde_json::from_str(&contents).map_err(|e| format!("Parse: {}", e))?;
-
-    println!("Deserialized. Decrypting keys...");
-
-    for provider in &mut settings.api_providers {
-        if !provider.api_key.is_empty() {
-            match crypto::decrypt_base64(&app_handle, &provider.api_key).await {
-                Ok(decrypted_key) => provider.api_key = decrypted_key,
-                Err(e) => {
-                    eprintln!("WARN: Failed to decrypt key for '{}': {}", provider.name, e);
-                    provider.api_key = String::new();
-                }
-            }
-        }
-    }
-    println!("Settings loaded.");
-    Ok(settings)
+#[tauri::command]
+pub async fn load_app_settings(
+    app_handle: AppHandle,
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+) -> Result<AppSettings, String> {
+    let key = state.key().map_err(|e| e.to_string())?;
+
+    // Upgrade any legacy settings.json on first load, then read everything back
+    // out of the database.
+    db::import_legacy_json(&db.0, &app_handle, &key)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Reject a store whose structure has been tampered with, or whose schema
+    // is newer than this build understands, before trusting its contents.
+    db::verify_manifest(&db.0, &app_handle, &key)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let api_providers = db::load_providers(&db.0).await.map_err(|e| e.to_string())?;
+    let (default_chat_settings, send_with_enter) =
+        db::load_app_meta(&db.0).await.map_err(|e| e.to_string())?;
+
+    Ok(AppSettings {
+        default_chat_settings,
+        api_providers,
+        send_with_enter,
+    })
 }
 
 #[tauri::command]
-pub async fn save_app_settings(app_handle: AppHandle, settings: AppSettings) -> Result<(), String> {
-    let path = get_settings_path(&app_handle)?;
-    println!("Saving settings to: {:?}", path);
-
-    if let Some(parent_dir) = path.parent() {
-        if !parent_dir.exists() {
-            println!(
-                "Parent directory does not exist, creating: {:?}",
-                parent_dir
-            );
-            fs::create_dir_all(parent_dir)
-                .map_err(|e| format!("Failed to create settings directory: {}", e))?;
-        }
-    } else {
-        return Err("Invalid settings file path (no parent directory).".to_string());
-    }
+pub async fn save_app_settings(
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    settings: AppSettings,
+) -> Result<(), String> {
+    let key = state.key().map_err(|e| e.to_string())?;
+
+    db::save_app_meta(&db.0, &settings.default_chat_settings, settings.send_with_enter)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Reconcile against the full incoming list so providers removed in the UI
+    // are deleted rather than lingering in the table.
+    db::sync_providers(&db.0, &key, &settings.api_providers)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    db::refresh_manifest(&db.0, &key)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
 
-    let mut settings_to_save = settings.clone();
-
-    for provider in &mut settings_to_save.api_providers {
-        if !provider.api_key.is_empty() {
-            match crypto::encrypt_to_base64(&app_handle, &provider.api_key).await {
-                Ok(encrypted_key_b64) => provider.api_key = encrypted_key_b64,
-                Err(e) => {
-                    return Err(format!("Failed encrypt key for {}: {}", provider.name, e));
-                }
-            }
-        }
-    }
+/// Rotate the encryption key: derive a fresh key from `new_password` (new salt,
+/// bumped generation), then decrypt every stored credential with the current
+/// key and re-encrypt it with the new one. Both generations' parameters are
+/// kept on disk across the re-encryption and the generation marker and
+/// integrity manifest are updated inside the same transaction as the
+/// re-encryption, so the store unlocks at every crash point and a re-run is
+/// idempotent; the superseded generation is pruned only once the sweep commits.
+#[tauri::command]
+pub async fn rotate_encryption_key(
+    app_handle: AppHandle,
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    new_password: String,
+) -> Result<(), String> {
+    let old_key = state.key().map_err(|e| e.to_string())?;
+
+    // Write the new parameters alongside the old ones first, so both key
+    // generations stay derivable while the database is re-encrypted. If the
+    // process dies at any point the store still unlocks: before the sweep
+    // commits the old key decrypts everything, after it the new key does.
+    let pending = crypto::prepare_rotation(&app_handle, &new_password)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    db::rotate_keys(&db.0, &old_key, pending.key(), pending.generation())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Every row is now re-encrypted under the new key, so drop the superseded
+    // generation from the parameters.
+    let new_key = pending.commit(&app_handle).map_err(|e| e.to_string())?;
+    state.set(new_key);
+    Ok(())
+}
 
-    let serialized_settings = serde_json::to_string_pretty(&settings_to_save)
-        .map_err(|e| format!("Failed serialize: {}", e))?;
+/// Upsert a single provider without rewriting the rest of the store.
+#[tauri::command]
+pub async fn save_provider(
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    provider: ApiProviderConfig,
+) -> Result<(), String> {
+    let key = state.key().map_err(|e| e.to_string())?;
+    db::upsert_provider(&db.0, &key, &provider)
+        .await
+        .map_err(|e| e.to_string())?;
+    db::refresh_manifest(&db.0, &key)
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    fs::write(&path, serialized_settings.as_bytes()) // Use the full path
-        .map_err(|e| format!("Failed write settings file: {}", e))?;
+/// Return the decrypted API key for a single provider. This is the explicit
+/// accessor the frontend calls when it actually needs a key to talk to a
+/// provider; `load_app_settings` only ever returns keys redacted.
+#[tauri::command]
+pub async fn get_provider_api_key(
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    id: String,
+) -> Result<Option<String>, String> {
+    let key = state.key().map_err(|e| e.to_string())?;
+    db::provider_api_key(&db.0, &key, &id)
+        .await
+        .map_err(|e| e.to_string())
+}
 
-        println!("Settings saved successfully to {:?}", path);
-        Ok(())
+/// Delete a single provider by id.
+#[tauri::command]
+pub async fn delete_provider(
+    state: State<'_, KeyStore>,
+    db: State<'_, Db>,
+    id: String,
+) -> Result<(), String> {
+    let key = state.key().map_err(|e| e.to_string())?;
+    db::delete_provider(&db.0, &id)
+        .await
+        .map_err(|e| e.to_string())?;
+    db::refresh_manifest(&db.0, &key)
+        .await
+        .map_err(|e| e.to_string())
 }